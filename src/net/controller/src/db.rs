@@ -0,0 +1,4 @@
+use serde::Serialize;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub(crate) struct UserId(pub(crate) String);
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use slab::Slab;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore as RawSemaphore;
+use tokio::sync::{mpsc, Notify};
+
+use crate::port::{ReadPort, WritePort};
+use crate::util::SimpleResult;
+
+mod metrics;
+mod server;
+
+pub(crate) mod db;
+pub(crate) mod port;
+pub(crate) mod stats;
+pub(crate) mod util;
+
+pub(crate) type PermuterId = u64;
+
+/// Data a server sends when it connects, before it's handed any work.
+#[derive(Deserialize)]
+pub(crate) struct ConnectServerData {
+    pub(crate) min_priority: f64,
+    pub(crate) num_cores: usize,
+    /// Capability labels this server advertises (e.g. supported
+    /// compiler/docker image tags, target architectures), matched against
+    /// each permuter's `required_labels` so work isn't routed to a server
+    /// that can only fail init.
+    #[serde(default)]
+    pub(crate) labels: HashSet<String>,
+}
+
+/// What the controller remembers about a connected server, independent of
+/// any particular permuter's job state.
+pub(crate) struct ConnectedServer {
+    pub(crate) min_priority: f64,
+    pub(crate) num_cores: usize,
+    pub(crate) labels: HashSet<String>,
+}
+
+/// The immutable, client-supplied description of a permuter, shared with
+/// every server it's dispatched to.
+#[derive(Serialize)]
+pub(crate) struct PermuterData {
+    pub(crate) compressed_source: Vec<u8>,
+    pub(crate) compressed_target_o_bin: Vec<u8>,
+    /// Labels a server must advertise (as a superset) before this permuter
+    /// will be dispatched to it.
+    #[serde(default)]
+    pub(crate) required_labels: HashSet<String>,
+}
+
+pub(crate) struct PermuterWork {
+    pub(crate) seed: u64,
+}
+
+/// Bounds how many not-yet-acknowledged seeds a permuter's producer can
+/// have queued up; `choose_work` calls `release()` as each one is handed
+/// off to a server.
+pub(crate) struct Semaphore(RawSemaphore);
+
+impl Semaphore {
+    pub(crate) fn release(&self) {
+        self.0.add_permits(1);
+    }
+}
+
+pub(crate) enum PermuterResult {
+    NeedWork,
+    Result(db::UserId, String, ServerUpdate),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ServerUpdate {
+    InitDone {},
+    InitFailed { reason: String },
+    Disconnect,
+    Result {
+        seed: u64,
+        has_source: bool,
+        #[serde(skip)]
+        compressed_source: Option<Vec<u8>>,
+    },
+}
+
+/// A permuter known to the controller, with its live scheduling state.
+pub(crate) struct Permuter {
+    pub(crate) client_id: db::UserId,
+    pub(crate) client_name: String,
+    pub(crate) data: Arc<PermuterData>,
+    pub(crate) energy_add: f64,
+    pub(crate) priority: f64,
+    pub(crate) stale: bool,
+    pub(crate) work_queue: VecDeque<PermuterWork>,
+    pub(crate) semaphore: Semaphore,
+    result_tx: mpsc::UnboundedSender<PermuterResult>,
+}
+
+impl Permuter {
+    pub(crate) fn send_result(&self, result: PermuterResult) {
+        let _ = self.result_tx.send(result);
+    }
+}
+
+#[cfg(test)]
+impl Permuter {
+    pub(crate) fn new_for_test(data: Arc<PermuterData>, priority: f64, energy_add: f64) -> Self {
+        let (result_tx, _) = mpsc::unbounded_channel();
+        Self {
+            client_id: db::UserId(String::new()),
+            client_name: String::new(),
+            data,
+            energy_add,
+            priority,
+            stale: false,
+            work_queue: VecDeque::new(),
+            semaphore: Semaphore(RawSemaphore::new(0)),
+            result_tx,
+        }
+    }
+}
+
+pub(crate) struct M {
+    pub(crate) permuters: HashMap<PermuterId, Permuter>,
+    pub(crate) servers: Slab<ConnectedServer>,
+}
+
+pub(crate) struct State {
+    pub(crate) m: std::sync::Mutex<M>,
+    pub(crate) docker_image: String,
+    pub(crate) new_work_notification: Notify,
+}
+
+impl State {
+    pub(crate) async fn log_stats(&self, record: stats::Record) -> SimpleResult<()> {
+        stats::append(record).await
+    }
+}
+
+/// The handshake message a server sends on connect: who it is, so
+/// `handle_connect_server` can attribute results and stats to it, with the
+/// `ConnectServerData` fields flattened into the same message.
+#[derive(Deserialize)]
+struct Hello {
+    client_id: String,
+    client_name: String,
+    #[serde(flatten)]
+    connect: ConnectServerData,
+}
+
+async fn handle_connection(stream: tokio::net::TcpStream, state: Arc<State>) -> SimpleResult<()> {
+    let (mut read_half, mut write_half) = tokio::io::split(stream);
+    let mut read_port = ReadPort::new(&mut read_half);
+    let mut write_port = WritePort::new(&mut write_half);
+
+    let hello: Hello = serde_json::from_slice(&read_port.recv().await?)?;
+    let who_id = db::UserId(hello.client_id);
+
+    server::handle_connect_server(
+        read_port,
+        write_port,
+        &who_id,
+        &hello.client_name,
+        &state,
+        hello.connect,
+    )
+    .await
+}
+
+#[tokio::main]
+async fn main() -> SimpleResult<()> {
+    let server_addr =
+        env::var("PERMUTER_CONTROLLER_ADDR").unwrap_or_else(|_| "0.0.0.0:17490".to_string());
+    let metrics_addr =
+        env::var("PERMUTER_METRICS_ADDR").unwrap_or_else(|_| "0.0.0.0:17491".to_string());
+    let docker_image =
+        env::var("PERMUTER_DOCKER_IMAGE").unwrap_or_else(|_| "decompme/permuter".to_string());
+
+    let state = Arc::new(State {
+        m: std::sync::Mutex::new(M {
+            permuters: HashMap::new(),
+            servers: Slab::new(),
+        }),
+        docker_image,
+        new_work_notification: Notify::new(),
+    });
+
+    tokio::spawn(metrics::serve(metrics_addr.parse()?));
+
+    let listener = TcpListener::bind(server_addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, state).await {
+                eprintln!("error handling server connection: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,166 @@
+//! Prometheus metrics for the scheduler, scraped over HTTP. These are a
+//! live complement to the append-only `stats::Record` log: they expose the
+//! current shape of the scheduler (connected servers, job states, queue
+//! depths, energy spread, observed iteration latency) rather than a history
+//! of discrete events.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_int_gauge, register_int_gauge_vec, Encoder, Histogram,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::util::SimpleResult;
+use crate::PermuterId;
+
+static CONNECTED_SERVERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "permuter_connected_servers",
+        "Number of servers currently connected to the controller."
+    )
+    .unwrap()
+});
+
+static SERVER_CORES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "permuter_server_cores",
+        "Cores advertised by each connected server.",
+        &["server_id"]
+    )
+    .unwrap()
+});
+
+static JOBS_BY_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "permuter_jobs_by_state",
+        "Number of permuter jobs in each JobState, per server. Aggregate across \
+         servers with `sum by (state) (...)`.",
+        &["server_id", "state"]
+    )
+    .unwrap()
+});
+
+static PERMUTER_QUEUE_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "permuter_work_queue_depth",
+        "Work queue depth of each permuter.",
+        &["permuter_id"]
+    )
+    .unwrap()
+});
+
+static PERMUTER_STALE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "permuter_stale",
+        "Whether a permuter is currently stale (1) and waiting on its client for more work.",
+        &["permuter_id"]
+    )
+    .unwrap()
+});
+
+static ENERGY_SPREAD: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "permuter_energy_spread_millienergy",
+        "Gap between the highest and lowest job energy on a server, in thousandths of an energy unit.",
+        &["server_id"]
+    )
+    .unwrap()
+});
+
+static TIME_COST_MS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "permuter_time_cost_ms",
+        "Observed time_cost_ms reported by servers for completed seeds.",
+        vec![10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0]
+    )
+    .unwrap()
+});
+
+pub(crate) fn on_server_connect(server_id: usize, num_cores: usize) {
+    CONNECTED_SERVERS.inc();
+    SERVER_CORES
+        .with_label_values(&[&server_id.to_string()])
+        .set(num_cores as i64);
+}
+
+pub(crate) fn on_server_disconnect(server_id: usize) {
+    CONNECTED_SERVERS.dec();
+    let _ = SERVER_CORES.remove_label_values(&[&server_id.to_string()]);
+    let _ = ENERGY_SPREAD.remove_label_values(&[&server_id.to_string()]);
+}
+
+pub(crate) fn set_server_cores(server_id: usize, num_cores: usize) {
+    SERVER_CORES
+        .with_label_values(&[&server_id.to_string()])
+        .set(num_cores as i64);
+}
+
+pub(crate) fn set_job_state_counts(server_id: usize, loading: usize, loaded: usize, failed: usize) {
+    let server_id = server_id.to_string();
+    JOBS_BY_STATE
+        .with_label_values(&[&server_id, "loading"])
+        .set(loading as i64);
+    JOBS_BY_STATE
+        .with_label_values(&[&server_id, "loaded"])
+        .set(loaded as i64);
+    JOBS_BY_STATE
+        .with_label_values(&[&server_id, "failed"])
+        .set(failed as i64);
+}
+
+pub(crate) fn remove_server_job_state_counts(server_id: usize) {
+    let server_id = server_id.to_string();
+    for state in ["loading", "loaded", "failed"] {
+        let _ = JOBS_BY_STATE.remove_label_values(&[&server_id, state]);
+    }
+}
+
+pub(crate) fn set_permuter_queue_depth(perm_id: PermuterId, depth: usize, stale: bool) {
+    let label = format!("{:?}", perm_id);
+    PERMUTER_QUEUE_DEPTH
+        .with_label_values(&[&label])
+        .set(depth as i64);
+    PERMUTER_STALE
+        .with_label_values(&[&label])
+        .set(stale as i64);
+}
+
+pub(crate) fn remove_permuter(perm_id: PermuterId) {
+    let label = format!("{:?}", perm_id);
+    let _ = PERMUTER_QUEUE_DEPTH.remove_label_values(&[&label]);
+    let _ = PERMUTER_STALE.remove_label_values(&[&label]);
+}
+
+pub(crate) fn set_energy_spread(server_id: usize, spread: f64) {
+    ENERGY_SPREAD
+        .with_label_values(&[&server_id.to_string()])
+        .set((spread * 1000.0) as i64);
+}
+
+pub(crate) fn observe_time_cost_ms(ms: f64) {
+    TIME_COST_MS.observe(ms);
+}
+
+async fn serve_req(_req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let metric_families = prometheus::gather();
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&metric_families, &mut buf)
+        .expect("encoding prometheus metrics should never fail");
+    Ok(Response::new(Body::from(buf)))
+}
+
+/// Serve `/metrics` (and everything else, for simplicity) until the process
+/// exits. Run this as its own background task alongside the controller's
+/// other listeners.
+pub(crate) async fn serve(addr: SocketAddr) -> SimpleResult<()> {
+    let make_svc =
+        make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(serve_req)) });
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::util::SimpleResult;
+
+/// Length-prefixed framing: a u32 (network byte order) byte count followed
+/// by that many raw bytes.
+pub(crate) struct ReadPort<'a> {
+    stream: &'a mut (dyn AsyncRead + Unpin + Send),
+}
+
+impl<'a> ReadPort<'a> {
+    pub(crate) fn new(stream: &'a mut (dyn AsyncRead + Unpin + Send)) -> Self {
+        Self { stream }
+    }
+
+    pub(crate) async fn recv(&mut self) -> SimpleResult<Vec<u8>> {
+        let len = self.stream.read_u32().await?;
+        let mut buf = vec![0; len as usize];
+        self.stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+pub(crate) struct WritePort<'a> {
+    stream: &'a mut (dyn AsyncWrite + Unpin + Send),
+}
+
+impl<'a> WritePort<'a> {
+    pub(crate) fn new(stream: &'a mut (dyn AsyncWrite + Unpin + Send)) -> Self {
+        Self { stream }
+    }
+
+    pub(crate) async fn send(&mut self, data: &[u8]) -> SimpleResult<()> {
+        self.stream.write_u32(data.len() as u32).await?;
+        self.stream.write_all(data).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn send_json<T: Serialize + ?Sized>(&mut self, value: &T) -> SimpleResult<()> {
+        self.send(&serde_json::to_vec(value)?).await
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
 use serde::Deserialize;
@@ -7,6 +7,7 @@ use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::TrySendError;
 
 use crate::db::UserId;
+use crate::metrics;
 use crate::port::{ReadPort, WritePort};
 use crate::stats;
 use crate::util::SimpleResult;
@@ -16,12 +17,36 @@ use crate::{
 };
 
 const SERVER_WORK_QUEUE_SIZE: usize = 100;
-const TIME_COST_MS_GUESS: f64 = 100.0;
+const INITIAL_TIME_COST_MS_GUESS: f64 = 100.0;
+const TIME_COST_EWMA_ALPHA: f64 = 0.2;
+
+/// Exponentially-weighted moving average update: blend `prev` with a new
+/// `sample`, weighted by `alpha`.
+fn ewma(prev: f64, alpha: f64, sample: f64) -> f64 {
+    (1.0 - alpha) * prev + alpha * sample
+}
+
+/// Whether a server's pipeline is as deep as it can usefully take, treating
+/// num_cores as at least 1 so a misconfigured/zero value doesn't stall
+/// dispatch entirely.
+fn is_at_capacity(in_flight: usize, num_cores: usize) -> bool {
+    in_flight >= num_cores.max(1)
+}
+
+/// Whether a server advertising `advertised` labels can run a permuter that
+/// requires `required` ones.
+fn server_can_run(required: &HashSet<String>, advertised: &HashSet<String>) -> bool {
+    required.is_subset(advertised)
+}
 
 #[derive(Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ServerMessage {
     NeedWork,
+    SetConfig {
+        min_priority: f64,
+        num_cores: usize,
+    },
     Update {
         permuter_id: PermuterId,
         time_cost_ms: f64,
@@ -38,10 +63,45 @@ enum JobState {
 struct Job {
     state: JobState,
     energy: f64,
+    // Exponentially-weighted moving average of this permuter's observed
+    // time_cost_ms on this server, used to charge energy on dispatch instead
+    // of a single fixed guess. Updated only from real `Result` updates, so an
+    // `InitFailed`/`Disconnect` can't skew it.
+    est_ms: f64,
+    // The est_ms that was charged for each dispatched, not-yet-reconciled
+    // seed, keyed by seed. With num_cores > 1 a single job can have several
+    // seeds in flight at once, each possibly charged at a different est_ms,
+    // so this can't be a single scalar: server_read looks up the exact
+    // amount to subtract for the seed a `Result` actually refers to, rather
+    // than whatever est_ms happens to be charging newer dispatches by the
+    // time it arrives. Doubles as the outstanding-seed count via `.len()`.
+    charged: HashMap<u64, f64>,
+}
+
+impl Job {
+    /// What to subtract from `energy` to reconcile a `Result`: the exact
+    /// amount charged for `result_seed` if it refers to one of our
+    /// dispatched seeds, else `est_ms` as a fallback for updates that don't
+    /// (InitDone/InitFailed/Disconnect).
+    fn take_charge(&mut self, result_seed: Option<u64>) -> f64 {
+        result_seed
+            .and_then(|seed| self.charged.remove(&seed))
+            .unwrap_or(self.est_ms)
+    }
 }
 
 struct ServerState {
     min_priority: f64,
+    labels: HashSet<String>,
+    num_cores: usize,
+    // Number of seeds currently dispatched to this server across all jobs,
+    // awaiting a `Result`. While this is below `num_cores`, server_write
+    // dispatches without waiting instead of round-tripping one seed at a
+    // time; once it reaches `num_cores`, server_write waits for a signal from
+    // server_read before each further dispatch. That is not a hard cap: any
+    // signal (not only a `Result`) lets one more seed out, so this can exceed
+    // `num_cores`.
+    in_flight: usize,
     jobs: HashMap<PermuterId, Job>,
 }
 
@@ -49,6 +109,7 @@ async fn server_read(
     port: &mut ReadPort<'_>,
     who_id: &UserId,
     who_name: &str,
+    server_id: usize,
     server_state: &Mutex<ServerState>,
     state: &State,
     more_work_tx: mpsc::Sender<()>,
@@ -57,45 +118,95 @@ async fn server_read(
         let msg = port.recv().await?;
         let msg: ServerMessage = serde_json::from_slice(&msg)?;
         let mut log_new = false;
-        if let ServerMessage::Update {
-            permuter_id: perm_id,
-            mut update,
-            time_cost_ms,
-        } = msg
-        {
-            if let ServerUpdate::Result {
-                ref mut compressed_source,
-                has_source: true,
-                ..
-            } = &mut update
-            {
-                *compressed_source = Some(port.recv().await?);
+        match msg {
+            ServerMessage::NeedWork => {}
+
+            ServerMessage::SetConfig {
+                min_priority,
+                num_cores,
+            } => {
+                let mut m = state.m.lock().unwrap();
+                let mut server_state = server_state.lock().unwrap();
+                server_state.min_priority = min_priority;
+                server_state.num_cores = num_cores;
+                if let Some(server) = m.servers.get_mut(server_id) {
+                    server.min_priority = min_priority;
+                    server.num_cores = num_cores;
+                }
+                drop(server_state);
+                drop(m);
+                metrics::set_server_cores(server_id, num_cores);
+                // Wake any sleeping choose_work loop so it re-evaluates its
+                // candidate set against the new min_priority right away,
+                // instead of waiting for the next unrelated wakeup.
+                state.new_work_notification.notify_waiters();
             }
-            let mut m = state.m.lock().unwrap();
-            let mut server_state = server_state.lock().unwrap();
 
-            // If we get back a message referring to a since-removed permuter,
-            // no need to do anything.
-            if let Some(job) = server_state.jobs.get_mut(&perm_id) {
-                if let Some(perm) = m.permuters.get_mut(&perm_id) {
-                    job.energy -= perm.energy_add * TIME_COST_MS_GUESS;
-                    job.energy += perm.energy_add * time_cost_ms;
+            ServerMessage::Update {
+                permuter_id: perm_id,
+                mut update,
+                time_cost_ms,
+            } => {
+                if let ServerUpdate::Result {
+                    ref mut compressed_source,
+                    has_source: true,
+                    ..
+                } = &mut update
+                {
+                    *compressed_source = Some(port.recv().await?);
+                }
+                let mut m = state.m.lock().unwrap();
+                let mut server_state = server_state.lock().unwrap();
 
-                    match update {
-                        ServerUpdate::InitDone { .. } => {
-                            job.state = JobState::Loaded;
-                            log_new = true;
-                        }
-                        ServerUpdate::InitFailed { .. } | ServerUpdate::Disconnect => {
-                            job.state = JobState::Failed;
+                let mut freed_slot = false;
+                let mut state_changed = false;
+
+                // If this Result refers to a specific dispatched seed, reconcile
+                // exactly what was charged for *that* seed rather than whatever
+                // est_ms happens to be charging newer in-flight dispatches for
+                // the same job by the time this arrives.
+                let result_seed = match &update {
+                    ServerUpdate::Result { seed, .. } => Some(*seed),
+                    _ => None,
+                };
+
+                // If we get back a message referring to a since-removed permuter,
+                // no need to do anything.
+                if let Some(job) = server_state.jobs.get_mut(&perm_id) {
+                    if let Some(perm) = m.permuters.get_mut(&perm_id) {
+                        let charged = job.take_charge(result_seed);
+                        job.energy -= perm.energy_add * charged;
+                        job.energy += perm.energy_add * time_cost_ms;
+
+                        match update {
+                            ServerUpdate::InitDone { .. } => {
+                                job.state = JobState::Loaded;
+                                log_new = true;
+                                state_changed = true;
+                            }
+                            ServerUpdate::InitFailed { .. } | ServerUpdate::Disconnect => {
+                                job.state = JobState::Failed;
+                                state_changed = true;
+                            }
+                            ServerUpdate::Result { .. } => {
+                                job.est_ms = ewma(job.est_ms, TIME_COST_EWMA_ALPHA, time_cost_ms);
+                                freed_slot = true;
+                                metrics::observe_time_cost_ms(time_cost_ms);
+                            }
                         }
-                        ServerUpdate::Result { .. } => {}
+                        perm.send_result(PermuterResult::Result(
+                            who_id.clone(),
+                            who_name.to_string(),
+                            update,
+                        ));
                     }
-                    perm.send_result(PermuterResult::Result(
-                        who_id.clone(),
-                        who_name.to_string(),
-                        update,
-                    ));
+                }
+
+                if freed_slot {
+                    server_state.in_flight = server_state.in_flight.saturating_sub(1);
+                }
+                if state_changed {
+                    publish_job_state_metrics(server_id, &server_state);
                 }
             }
         }
@@ -124,7 +235,11 @@ enum ToSend {
     Remove,
 }
 
-async fn choose_work(server_state: &Mutex<ServerState>, state: &State) -> (PermuterId, ToSend) {
+async fn choose_work(
+    server_id: usize,
+    server_state: &Mutex<ServerState>,
+    state: &State,
+) -> (PermuterId, ToSend) {
     let mut wait_for = None;
     loop {
         if let Some(waiter) = wait_for {
@@ -134,19 +249,24 @@ async fn choose_work(server_state: &Mutex<ServerState>, state: &State) -> (Permu
         let mut m = state.m.lock().unwrap();
         let mut server_state = server_state.lock().unwrap();
 
-        // If possible, send a new permuter.
-        if let Some((&perm_id, perm)) = m
-            .permuters
-            .iter()
-            .find(|(&perm_id, _)| !server_state.jobs.contains_key(&perm_id))
-        {
+        // If possible, send a new permuter whose requirements this server can
+        // satisfy. Permuters that need labels the server doesn't advertise
+        // are skipped rather than dispatched and left to fail during init.
+        if let Some((&perm_id, perm)) = m.permuters.iter().find(|(&perm_id, perm)| {
+            !server_state.jobs.contains_key(&perm_id)
+                && server_can_run(&perm.data.required_labels, &server_state.labels)
+        }) {
             server_state.jobs.insert(
                 perm_id,
                 Job {
                     state: JobState::Loading,
                     energy: 0.0,
+                    est_ms: INITIAL_TIME_COST_MS_GUESS,
+                    charged: HashMap::new(),
                 },
             );
+            publish_job_state_metrics(server_id, &server_state);
+            metrics::set_permuter_queue_depth(perm_id, perm.work_queue.len(), perm.stale);
             return (
                 perm_id,
                 ToSend::Add(
@@ -161,18 +281,30 @@ async fn choose_work(server_state: &Mutex<ServerState>, state: &State) -> (Permu
         let mut best_cost = 0.0;
         let mut best: Option<(PermuterId, &mut Job)> = None;
         let min_priority = server_state.min_priority;
+        let labels = server_state.labels.clone();
         for (&perm_id, job) in server_state.jobs.iter_mut() {
             if let Some(perm) = m.permuters.get(&perm_id) {
                 if matches!(job.state, JobState::Loaded)
                     && !perm.stale
                     && perm.priority >= min_priority
+                    && server_can_run(&perm.data.required_labels, &labels)
                     && (best.is_none() || job.energy < best_cost)
                 {
                     best_cost = job.energy;
                     best = Some((perm_id, job));
                 }
             } else {
-                server_state.jobs.remove(&perm_id);
+                // The permuter was removed. ToSend::Remove itself tells the
+                // server to cancel and abandon whatever it has queued or
+                // running for it, and it'll never send back a Result for
+                // those seeds to free them up, so release them here instead
+                // of leaking them out of in_flight forever.
+                if let Some(job) = server_state.jobs.remove(&perm_id) {
+                    server_state.in_flight =
+                        server_state.in_flight.saturating_sub(job.charged.len());
+                }
+                publish_job_state_metrics(server_id, &server_state);
+                metrics::remove_permuter(perm_id);
                 return (perm_id, ToSend::Remove);
             }
         }
@@ -195,15 +327,19 @@ async fn choose_work(server_state: &Mutex<ServerState>, state: &State) -> (Permu
                 // notified.
                 perm.send_result(PermuterResult::NeedWork);
                 perm.stale = true;
+                metrics::set_permuter_queue_depth(perm_id, perm.work_queue.len(), perm.stale);
                 wait_for = None;
                 continue;
             }
             Some(work) => work,
         };
         perm.semaphore.release();
+        metrics::set_permuter_queue_depth(perm_id, perm.work_queue.len(), perm.stale);
 
         let min_energy = job.energy;
-        job.energy += perm.energy_add * TIME_COST_MS_GUESS;
+        job.charged.insert(work.seed, job.est_ms);
+        job.energy += perm.energy_add * job.est_ms;
+        server_state.in_flight += 1;
 
         // Adjust energies to be around zero, to avoid problems with float
         // imprecision, and to ensure that new permuters that come in with
@@ -212,10 +348,30 @@ async fn choose_work(server_state: &Mutex<ServerState>, state: &State) -> (Permu
             job.energy -= min_energy;
         }
 
+        let mut energies = server_state.jobs.values().map(|j| j.energy);
+        if let Some(first) = energies.next() {
+            let (lo, hi) = energies.fold((first, first), |(lo, hi), e| (lo.min(e), hi.max(e)));
+            metrics::set_energy_spread(server_id, hi - lo);
+        }
+
         return (perm_id, ToSend::Work(work));
     }
 }
 
+fn publish_job_state_metrics(server_id: usize, server_state: &ServerState) {
+    let mut loading = 0;
+    let mut loaded = 0;
+    let mut failed = 0;
+    for job in server_state.jobs.values() {
+        match job.state {
+            JobState::Loading => loading += 1,
+            JobState::Loaded => loaded += 1,
+            JobState::Failed => failed += 1,
+        }
+    }
+    metrics::set_job_state_counts(server_id, loading, loaded, failed);
+}
+
 async fn send_work(
     port: &mut WritePort<'_>,
     perm_id: PermuterId,
@@ -243,6 +399,15 @@ async fn send_work(
             port.send(&permuter.compressed_target_o_bin).await?;
         }
         ToSend::Remove => {
+            // Tell the server to abandon any queued or already-running
+            // seeds for this permuter before telling it to forget about the
+            // permuter entirely, so cycles aren't wasted on work whose
+            // results would just be dropped.
+            port.send_json(&json!({
+                "type": "cancel",
+                "permuter": perm_id,
+            }))
+            .await?;
             port.send_json(&json!({
                 "type": "remove",
                 "permuter": perm_id,
@@ -255,14 +420,24 @@ async fn send_work(
 
 async fn server_write(
     port: &mut WritePort<'_>,
+    server_id: usize,
     server_state: &Mutex<ServerState>,
     state: &State,
     mut more_work_rx: mpsc::Receiver<()>,
 ) -> SimpleResult<()> {
     loop {
-        let (perm_id, to_send) = choose_work(server_state, state).await;
+        let (perm_id, to_send) = choose_work(server_id, server_state, state).await;
+        let is_work = matches!(to_send, ToSend::Work(_));
         send_work(port, perm_id, to_send).await?;
-        if matches!(more_work_rx.recv().await, None) {
+
+        // Keep up to num_cores seeds in flight instead of round-tripping one
+        // at a time: only wait for a result (or other signal) once the
+        // server's pipeline is as deep as it can usefully take.
+        let at_capacity = {
+            let server_state = server_state.lock().unwrap();
+            is_at_capacity(server_state.in_flight, server_state.num_cores)
+        };
+        if is_work && at_capacity && matches!(more_work_rx.recv().await, None) {
             break;
         }
     }
@@ -287,26 +462,35 @@ pub(crate) async fn handle_connect_server<'a>(
 
     let mut server_state = Mutex::new(ServerState {
         min_priority: data.min_priority,
+        labels: data.labels.clone(),
+        num_cores: data.num_cores,
+        in_flight: 0,
         jobs: HashMap::new(),
     });
 
     let id = state.m.lock().unwrap().servers.insert(ConnectedServer {
         min_priority: data.min_priority,
         num_cores: data.num_cores,
+        labels: data.labels.clone(),
     });
+    metrics::on_server_connect(id, data.num_cores);
 
     let r = tokio::try_join!(
         server_read(
             &mut read_port,
             who_id,
             who_name,
+            id,
             &server_state,
             state,
             more_work_tx
         ),
-        server_write(&mut write_port, &server_state, state, more_work_rx)
+        server_write(&mut write_port, id, &server_state, state, more_work_rx)
     );
 
+    metrics::on_server_disconnect(id);
+    metrics::remove_server_job_state_counts(id);
+
     {
         let mut m = state.m.lock().unwrap();
         for (&perm_id, job) in &server_state.get_mut().unwrap().jobs {
@@ -326,3 +510,158 @@ pub(crate) async fn handle_connect_server<'a>(
     r?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ewma_blends_toward_the_new_sample() {
+        assert_eq!(ewma(100.0, 0.2, 100.0), 100.0);
+        assert_eq!(ewma(100.0, 0.2, 200.0), 120.0);
+        assert_eq!(ewma(100.0, 1.0, 200.0), 200.0);
+        assert_eq!(ewma(100.0, 0.0, 200.0), 100.0);
+    }
+
+    #[test]
+    fn set_config_parses_from_wire_json() {
+        let msg: ServerMessage = serde_json::from_str(
+            r#"{"type": "set_config", "min_priority": -1.5, "num_cores": 4}"#,
+        )
+        .unwrap();
+        match msg {
+            ServerMessage::SetConfig {
+                min_priority,
+                num_cores,
+            } => {
+                assert_eq!(min_priority, -1.5);
+                assert_eq!(num_cores, 4);
+            }
+            _ => panic!("expected SetConfig"),
+        }
+    }
+
+    #[test]
+    fn at_capacity_treats_zero_cores_as_one() {
+        assert!(!is_at_capacity(0, 1));
+        assert!(is_at_capacity(1, 1));
+        assert!(!is_at_capacity(0, 4));
+        assert!(is_at_capacity(4, 4));
+        assert!(!is_at_capacity(0, 0));
+        assert!(is_at_capacity(1, 0));
+    }
+
+    #[tokio::test]
+    async fn removing_a_permuter_frees_its_in_flight_seeds() {
+        use crate::{Permuter, M};
+        use slab::Slab;
+        use tokio::sync::Notify;
+
+        let perm_id: PermuterId = 1;
+        let data = Arc::new(PermuterData {
+            compressed_source: vec![],
+            compressed_target_o_bin: vec![],
+            required_labels: HashSet::new(),
+        });
+        let mut permuter = Permuter::new_for_test(data, 0.0, 1.0);
+        permuter.work_queue.push_back(PermuterWork { seed: 1 });
+        permuter.work_queue.push_back(PermuterWork { seed: 2 });
+
+        let mut permuters = HashMap::new();
+        permuters.insert(perm_id, permuter);
+        let state = State {
+            m: std::sync::Mutex::new(M {
+                permuters,
+                servers: Slab::new(),
+            }),
+            docker_image: String::new(),
+            new_work_notification: Notify::new(),
+        };
+
+        let server_state = Mutex::new(ServerState {
+            min_priority: 0.0,
+            labels: HashSet::new(),
+            num_cores: 4,
+            in_flight: 0,
+            jobs: HashMap::new(),
+        });
+
+        // First call registers the job (ToSend::Add); mark it Loaded so the
+        // next calls actually dispatch its queued seeds.
+        let (_, to_send) = choose_work(0, &server_state, &state).await;
+        assert!(matches!(to_send, ToSend::Add(..)));
+        server_state
+            .lock()
+            .unwrap()
+            .jobs
+            .get_mut(&perm_id)
+            .unwrap()
+            .state = JobState::Loaded;
+
+        let (_, to_send) = choose_work(0, &server_state, &state).await;
+        assert!(matches!(to_send, ToSend::Work(_)));
+        let (_, to_send) = choose_work(0, &server_state, &state).await;
+        assert!(matches!(to_send, ToSend::Work(_)));
+        assert_eq!(server_state.lock().unwrap().in_flight, 2);
+
+        // Remove the permuter and dispatch again: choose_work should notice
+        // it's gone, tear down its job, and release its in-flight seeds
+        // instead of leaking them (the server will never send a Result for
+        // seeds it's been told to cancel and abandon).
+        state.m.lock().unwrap().permuters.remove(&perm_id);
+        let (_, to_send) = choose_work(0, &server_state, &state).await;
+        assert!(matches!(to_send, ToSend::Remove));
+        assert_eq!(server_state.lock().unwrap().in_flight, 0);
+    }
+
+    #[test]
+    fn charged_reconciles_against_the_seed_a_result_refers_to() {
+        let mut job = Job {
+            state: JobState::Loaded,
+            energy: 0.0,
+            est_ms: 100.0,
+            charged: HashMap::new(),
+        };
+
+        // Two seeds dispatched back to back at different est_ms.
+        job.charged.insert(1, 100.0);
+        job.est_ms = 150.0;
+        job.charged.insert(2, 150.0);
+
+        // take_charge should return what was actually charged for the seed
+        // a Result refers to, not whatever est_ms is charging newer
+        // dispatches by the time it arrives.
+        assert_eq!(job.take_charge(Some(1)), 100.0);
+        assert_eq!(job.take_charge(Some(2)), 150.0);
+        assert!(job.charged.is_empty());
+
+        // InitDone/InitFailed/Disconnect don't refer to a seed: fall back
+        // to the current est_ms without touching `charged`.
+        job.charged.insert(3, 200.0);
+        assert_eq!(job.take_charge(None), 150.0);
+        assert_eq!(job.charged.len(), 1);
+    }
+
+    #[test]
+    fn server_can_run_checks_label_superset() {
+        let empty = HashSet::new();
+        let rust: HashSet<String> = ["rust".to_string()].into_iter().collect();
+        let rust_and_c: HashSet<String> = ["rust".to_string(), "c".to_string()]
+            .into_iter()
+            .collect();
+        let only_c: HashSet<String> = ["c".to_string()].into_iter().collect();
+
+        // No requirements: any server can run it.
+        assert!(server_can_run(&empty, &empty));
+        assert!(server_can_run(&empty, &rust));
+
+        // Disjoint labels: the server can't satisfy it.
+        assert!(!server_can_run(&rust, &only_c));
+
+        // The server advertises a strict superset of what's required.
+        assert!(server_can_run(&rust, &rust_and_c));
+
+        // Requirements beyond what's advertised still aren't satisfied.
+        assert!(!server_can_run(&rust_and_c, &rust));
+    }
+}
@@ -0,0 +1 @@
+pub(crate) type SimpleResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;